@@ -0,0 +1,69 @@
+use crate::MBError;
+use serde::Deserialize;
+use std::path::Path;
+
+/// Layered settings loaded from a `--config <file.toml>` file. Every field is optional so a
+/// settings file can cover only part of the configuration, with the remainder supplied on the
+/// command line; CLI flags always take precedence over a value found here.
+#[derive(Debug, Deserialize, Default)]
+pub struct MBSettings {
+    pub filter: Option<FilterSettings>,
+    pub server: Option<ServerSettings>,
+    pub output: Option<OutputSettings>,
+    pub io: Option<IoSettings>,
+    pub acl: Option<AclSettings>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct FilterSettings {
+    pub k: Option<String>,
+    pub l: Option<String>,
+    pub m: Option<String>,
+    pub pthresh: Option<String>,
+    pub dead_time: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct ServerSettings {
+    pub listen: Option<String>,
+    pub unix: Option<String>,
+    pub max_connections: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct OutputSettings {
+    pub file: Option<String>,
+    pub target_size: Option<u64>,
+}
+
+/// Tuning for the bounded channel that decouples the FPGA reader from whichever sink (output
+/// file or websocket client) is draining it.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct IoSettings {
+    /// number of frames the channel can hold before the configured backpressure policy kicks in
+    pub backlog: Option<usize>,
+    /// how long the writer may take on a single send before the connection is torn down
+    pub timeout_ms: Option<u64>,
+    /// minimum delay between reads from the hardware FIFO
+    pub throttle_ms: Option<u64>,
+    /// when the channel is full, drop the oldest queued frame instead of blocking the reader
+    pub drop_oldest: Option<bool>,
+}
+
+/// Client connection-acceptance filter for the `server` subcommand. Entries are CIDR ranges,
+/// e.g. `"10.0.0.0/8"`. With no `allow` entries the server defaults to loopback-only, since
+/// starting a measurement physically drives lab hardware.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct AclSettings {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+}
+
+impl MBSettings {
+    /// Reads and parses a settings file, rejecting malformed TOML as `MBError::InvalidInput`
+    /// before any hardware is touched.
+    pub fn from_file(path: &Path) -> Result<Self, MBError> {
+        let contents = std::fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|_| MBError::InvalidInput)
+    }
+}
@@ -3,12 +3,23 @@ use std::fmt;
 use std::io;
 use std::error::Error;
 
+mod config;
+pub use config::{
+    MBSettings,
+    FilterSettings,
+    ServerSettings,
+    OutputSettings,
+    IoSettings,
+    AclSettings,
+};
+
 #[derive(Debug)]
 pub enum MBError {
     WrongState,
     InvalidInput,
     FilterError(MBFError),
     IOError(io::Error),
+    BindError(io::Error),
 }
 
 impl From<MBFError> for MBError {
@@ -31,6 +42,7 @@ impl fmt::Display for MBError {
             \nthe state of the filter and bring the filter to the ready state"),
             MBError::FilterError(e) => write!(f, "The Filter-hardware reported an error: {}", e),
             MBError::IOError(e) => write!(f, "An error occured while handeling the File: {}", e),
+            MBError::BindError(e) => write!(f, "Could not bind the server socket: {}", e),
         }
     }
 }
@@ -42,6 +54,7 @@ impl Error for MBError {
             MBError::InvalidInput => None,
             MBError::FilterError(e) => Some(e),
             MBError::IOError(e) => Some(e),
+            MBError::BindError(e) => Some(e),
         }
     }
 }
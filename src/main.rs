@@ -6,31 +6,312 @@ use moessbauer_filter::{
     MBFilter,
     MBFState,
 };
-//use moessbauer_data::{
-//    MeasuredPeak,
-//};
-use std::error::Error;
-use std::fs::File;
-use std::io::{
-    BufWriter,
-    Write,
+use moessbauer_data::{
+    MeasuredPeak,
+};
+use rumqttc::{
+    AsyncClient,
+    LastWill,
+    MqttOptions,
+    QoS,
 };
+use std::error::Error;
 use std::path::Path;
-use mbfilter::MBError;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use std::time::Duration;
+use std::collections::VecDeque;
+use tokio::sync::Notify;
+use tokio::io::{
+    AsyncBufReadExt,
+    AsyncWriteExt,
+    BufReader,
+};
+use mbfilter::{
+    MBError,
+    MBSettings,
+    IoSettings,
+    AclSettings,
+};
+use ipnet::IpNet;
 use log::{
     debug,
+    warn,
 };
 use warp::Filter;
+use warp::Reply;
 use futures_util::{
     SinkExt,
     StreamExt,
 };
+use tokio::net::{
+    TcpListener,
+    TcpStream,
+    UnixListener,
+};
+use tokio_stream::wrappers::UnixListenerStream;
+use serde_json::json;
 
 #[derive(Debug)]
 struct MBHTTPError(&'static str);
 
 impl warp::reject::Reject for MBHTTPError {}
 
+#[derive(Debug)]
+struct ServerSaturated;
+
+impl warp::reject::Reject for ServerSaturated {}
+
+#[derive(Debug)]
+struct ClientNotAllowed;
+
+impl warp::reject::Reject for ClientNotAllowed {}
+
+// a single reservation made by `admission_control`, released exactly once no matter which of
+// several independent paths ends the connection (a later filter in the chain rejecting before
+// the websocket upgrade, or -- once the upgrade has happened -- whichever of the reader, writer
+// and control tasks is the last of the three still holding a clone). The reservation is owned by
+// an `Arc<ConnectionSlotInner>`, so cloning a `ConnectionSlot` just clones the `Arc`: the
+// decrement in `ConnectionSlotInner`'s `Drop` only ever runs once, when the *last* clone goes
+// away, instead of whichever clone happens to be dropped first -- and each call site is spared
+// doing its own `fetch_sub` and risking the counter being decremented more than once.
+struct ConnectionSlotInner {
+    active_connections: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlotInner {
+    fn drop(&mut self) {
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone)]
+struct ConnectionSlot {
+    inner: Arc<ConnectionSlotInner>,
+}
+
+// tuning for the bounded channel that decouples a reader task draining the hardware FIFO from
+// whichever sink (output file or websocket client) is writing it out
+#[derive(Debug, Clone, Copy)]
+struct IoTuning {
+    backlog: usize,
+    timeout_ms: u64,
+    throttle_ms: u64,
+    drop_oldest: bool,
+}
+
+impl IoTuning {
+    fn from_matches(matches: &clap::ArgMatches, io_settings: &IoSettings) -> Result<Self, MBError> {
+        let backlog = match matches.value_of("backlog") {
+            Some(v) => v.parse().map_err(|_| MBError::InvalidInput)?,
+            None => io_settings.backlog.unwrap_or(32),
+        };
+        let timeout_ms = match matches.value_of("timeout-ms") {
+            Some(v) => v.parse().map_err(|_| MBError::InvalidInput)?,
+            None => io_settings.timeout_ms.unwrap_or(1000),
+        };
+        let throttle_ms = match matches.value_of("throttle-ms") {
+            Some(v) => v.parse().map_err(|_| MBError::InvalidInput)?,
+            None => io_settings.throttle_ms.unwrap_or(0),
+        };
+        let drop_oldest = matches.is_present("drop-oldest") || io_settings.drop_oldest.unwrap_or(false);
+        Ok(IoTuning { backlog, timeout_ms, throttle_ms, drop_oldest })
+    }
+}
+
+// bounded queue that decouples the FPGA reader from whichever sink (output file or websocket
+// client) is draining it. A plain `tokio::sync::mpsc` channel only lets its receiver pop, which
+// made a correct drop-oldest policy impossible without sharing the receiver into the producer
+// task too -- and that let the consumer block inside `recv().await` while holding the very lock
+// the producer needed to evict an entry, so drop-oldest only ever worked by accident. Here the
+// push (which makes the drop-oldest decision) and the pop happen under the same lock, so the two
+// sides can never race, and whichever side waits does so on a `Notify`, never while holding it.
+struct FrameQueue {
+    state: Mutex<FrameQueueState>,
+    capacity: usize,
+    frame_available: Notify,
+    space_available: Notify,
+}
+
+struct FrameQueueState {
+    frames: VecDeque<Vec<u8>>,
+    closed: bool,
+}
+
+enum PushOutcome {
+    Pushed,
+    Dropped,
+    Closed(Vec<u8>),
+}
+
+impl FrameQueue {
+    fn new(capacity: usize) -> Self {
+        FrameQueue {
+            state: Mutex::new(FrameQueueState { frames: VecDeque::with_capacity(capacity), closed: false }),
+            capacity,
+            frame_available: Notify::new(),
+            space_available: Notify::new(),
+        }
+    }
+
+    // pushes a frame, optionally evicting the oldest queued frame to make room instead of
+    // waiting for the consumer to free space
+    async fn push(&self, frame: Vec<u8>, drop_oldest: bool) -> PushOutcome {
+        loop {
+            let mut state = self.state.lock().await;
+            if state.closed {
+                return PushOutcome::Closed(frame);
+            }
+            if state.frames.len() < self.capacity {
+                state.frames.push_back(frame);
+                drop(state);
+                self.frame_available.notify_one();
+                return PushOutcome::Pushed;
+            }
+            if drop_oldest {
+                state.frames.pop_front();
+                state.frames.push_back(frame);
+                drop(state);
+                self.frame_available.notify_one();
+                return PushOutcome::Dropped;
+            }
+            drop(state);
+            self.space_available.notified().await;
+        }
+    }
+
+    async fn pop(&self) -> Option<Vec<u8>> {
+        loop {
+            let mut state = self.state.lock().await;
+            if let Some(frame) = state.frames.pop_front() {
+                drop(state);
+                self.space_available.notify_one();
+                return Some(frame);
+            }
+            if state.closed {
+                return None;
+            }
+            drop(state);
+            self.frame_available.notified().await;
+        }
+    }
+
+    // wakes any task currently blocked in push() or pop() so it can observe the closed queue
+    // instead of waiting forever
+    async fn close(&self) {
+        let mut state = self.state.lock().await;
+        state.closed = true;
+        drop(state);
+        self.frame_available.notify_one();
+        self.space_available.notify_one();
+    }
+}
+
+// merges the --allow/--deny CIDR lists from the command line with the [acl] section of
+// --config (CLI entries take precedence over the file) and parses them into `IpNet`s
+fn resolve_acl(matches: &clap::ArgMatches, acl_settings: &AclSettings) -> Result<(Vec<IpNet>, Vec<IpNet>), MBError> {
+    let allow_strings: Vec<String> = match matches.values_of("allow") {
+        Some(values) => values.map(String::from).collect(),
+        None => acl_settings.allow.clone().unwrap_or_default(),
+    };
+    let deny_strings: Vec<String> = match matches.values_of("deny") {
+        Some(values) => values.map(String::from).collect(),
+        None => acl_settings.deny.clone().unwrap_or_default(),
+    };
+    let parse_all = |cidrs: Vec<String>| -> Result<Vec<IpNet>, MBError> {
+        cidrs.iter()
+            .map(|cidr| cidr.parse::<IpNet>().map_err(|_| MBError::InvalidInput))
+            .collect()
+    };
+    Ok((parse_all(allow_strings)?, parse_all(deny_strings)?))
+}
+
+// connection-acceptance filter run ahead of everything else in the route: rejects disallowed
+// peers with HTTP 403 before any `MBFilter` lock or configuration attempt is made. Because
+// starting a measurement physically drives lab hardware, an empty `allow` means loopback-only
+// rather than "anyone who can reach the port", whether or not any `deny` ranges are configured;
+// a remote peer is only admitted when it matches an explicit `allow` entry
+fn client_acl(
+    allow: Arc<Vec<IpNet>>,
+    deny: Arc<Vec<IpNet>>,
+) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
+    warp::filters::addr::remote().and_then(move |remote: Option<std::net::SocketAddr>| {
+        let allow = allow.clone();
+        let deny = deny.clone();
+        async move {
+            // no peer address (e.g. a Unix domain socket connection) can't be remote
+            let ip = match remote {
+                Some(addr) => addr.ip(),
+                None => return Ok(()),
+            };
+            if deny.iter().any(|net| net.contains(&ip)) {
+                return Err(warp::reject::custom(ClientNotAllowed));
+            }
+            if !allow.is_empty() {
+                return if allow.iter().any(|net| net.contains(&ip)) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(ClientNotAllowed))
+                };
+            }
+            if ip.is_loopback() {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(ClientNotAllowed))
+            }
+        }
+    })
+}
+
+// common `--backlog`/`--timeout-ms`/`--throttle-ms`/`--drop-oldest` knobs for the reader/writer
+// channel, shared by the `start` and `server` subcommands
+fn io_tuning_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("backlog")
+            .long("backlog")
+            .value_name("frames")
+            .help("capacity of the channel between the FPGA reader and the sink, falls back to\
+                the [io] section of --config, defaults to 32")
+            .takes_value(true),
+        Arg::with_name("timeout-ms")
+            .long("timeout-ms")
+            .value_name("milliseconds")
+            .help("how long the sink may take to accept a buffer before the connection is torn\
+                down, falls back to the [io] section of --config, defaults to 1000")
+            .takes_value(true),
+        Arg::with_name("throttle-ms")
+            .long("throttle-ms")
+            .value_name("milliseconds")
+            .help("minimum delay between reads from the hardware FIFO, falls back to the [io]\
+                section of --config, defaults to 0 (no throttling)")
+            .takes_value(true),
+        Arg::with_name("drop-oldest")
+            .long("drop-oldest")
+            .help("when the channel is full, drop the oldest queued buffer instead of blocking\
+                the reader; falls back to the [io] section of --config")
+            .takes_value(false),
+    ]
+}
+
+// address of the control socket the `server` subcommand binds and the `stop`/`status`
+// subcommands connect to as thin clients, shared by all three since the filter hardware is
+// owned by whichever process is running `server`
+const DEFAULT_CONTROL_ADDR: &str = "127.0.0.1:7878";
+
+fn control_arg() -> Arg<'static, 'static> {
+    Arg::with_name("control")
+        .long("control")
+        .short("C")
+        .value_name("host:port")
+        .help("address of the server's control socket, used to query status or request a stop\
+            without opening the hardware directly")
+        .takes_value(true)
+        .default_value(DEFAULT_CONTROL_ADDR)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
@@ -49,160 +330,615 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
                 .short("k")
                 .long("k-param")
                 .value_name("flank steepnes")
-                .help("length of the rising and falling flank of the trapezoidal filter in filter clock cycles (8ns)")
+                .help("length of the rising and falling flank of the trapezoidal filter in filter clock cycles (8ns),\
+                    falls back to the [filter] section of --config if omitted")
                 .takes_value(true)
-                .required(true)
                 .index(1))
             .arg(Arg::with_name("l")
                 .short("l")
                 .long("l-param")
                 .value_name("plateau length")
-                .help("length of the plateau of the trapezoidal filters in filter clock cycles")
+                .help("length of the plateau of the trapezoidal filters in filter clock cycles,\
+                    falls back to the [filter] section of --config if omitted")
                 .takes_value(true)
-                .required(true)
                 .index(2))
             .arg(Arg::with_name("m")
                 .short("m")
                 .long("m-factor")
                 .value_name("decay time factor")
-                .help("multiplication factor of the filter. Sets the decay time that the filter is sensitive to")
+                .help("multiplication factor of the filter. Sets the decay time that the filter is sensitive to,\
+                    falls back to the [filter] section of --config if omitted")
                 .takes_value(true)
-                .required(true)
                 .index(3))
             .arg(Arg::with_name("pthresh")
                 .short("p")
                 .long("pthresh")
                 .value_name("peak threshhold")
-                .help("minimum value of the peak to be considered as a signal")
+                .help("minimum value of the peak to be considered as a signal,\
+                    falls back to the [filter] section of --config if omitted")
                 .takes_value(true)
-                .required(true)
                 .index(4))
             .arg(Arg::with_name("dead-time")
                 .short("d")
                 .long("dtime")
                 .value_name("dead time")
-                .help("the time in which the filter coalesses multiple peaks into a single peak for noise reduction")
+                .help("the time in which the filter coalesses multiple peaks into a single peak for noise reduction,\
+                    falls back to the [filter] section of --config if omitted")
                 .takes_value(true)
-                .required(true)
-                .index(5)))
+                .index(5))
+            .arg(Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("file.toml")
+                .help("TOML file with a [filter] section supplying any of the above parameters; values given on\
+                    the command line take precedence over the file")
+                .takes_value(true)))
         .subcommand(SubCommand::with_name("server")
             .about("Turn the control program into a server that opens a specified port and waits for client connections")
             .arg(Arg::with_name("listen")
                 .short("l")
                 .long("listen")
                 .value_name("listen")
-                .help("the IP address and port that the server should listen on")
+                .help("the IP address and port that the server should listen on, falls back to the [server] section\
+                    of --config if omitted")
                 .takes_value(true)
-                .required(true)
-                .index(1)))
+                .conflicts_with("unix"))
+            .arg(Arg::with_name("unix")
+                .short("u")
+                .long("unix")
+                .value_name("path")
+                .help("path to a Unix domain socket to listen on instead of a TCP address, useful when the control\
+                    program is supervised on the same host rather than reached over the network; falls back to\
+                    the [server] section of --config if omitted")
+                .takes_value(true)
+                .conflicts_with("listen"))
+            .arg(Arg::with_name("max-connections")
+                .long("max-connections")
+                .value_name("count")
+                .help("maximum number of simultaneous client connections accepted; the hardware filter is a\
+                    single resource so this defaults to 1 and additional clients are answered with HTTP 503")
+                .takes_value(true))
+            .arg(Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("file.toml")
+                .help("TOML file with a [server] section supplying listen/unix/max-connections; values given on\
+                    the command line take precedence over the file")
+                .takes_value(true))
+            .arg(Arg::with_name("allow")
+                .long("allow")
+                .value_name("cidr")
+                .help("CIDR range a client is allowed to connect from, may be given multiple times; falls back to\
+                    the [acl] section of --config, defaults to loopback-only if neither is given")
+                .takes_value(true)
+                .multiple(true))
+            .arg(Arg::with_name("deny")
+                .long("deny")
+                .value_name("cidr")
+                .help("CIDR range a client is refused from even if it matches --allow, may be given multiple\
+                    times; falls back to the [acl] section of --config")
+                .takes_value(true)
+                .multiple(true))
+            .arg(control_arg())
+            .args(&io_tuning_args()))
         .subcommand(SubCommand::with_name("start")
             .about("command that starts the measurement. The filter has to be configured to be able to start")
             .arg(Arg::with_name("output file")
                 .short("o")
                 .long("ofile")
                 .value_name("output file")
-                .help("file path where the results of the measurement are written to CAUTION: Be aware of disk space")
+                .help("file path where the results of the measurement are written to CAUTION: Be aware of disk space,\
+                    falls back to the [output] section of --config if omitted")
                 .takes_value(true)
-                .index(1)
-                .required(true))
+                .index(1))
             .arg(Arg::with_name("target file size")
                 .short("s")
                 .long("target-file-size")
-                .help("The file size that should be collected before the measurement is automatically stopped")
+                .help("The file size that should be collected before the measurement is automatically stopped,\
+                    falls back to the [output] section of --config if omitted")
+                .takes_value(true)
+                .index(2))
+            .arg(Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("file.toml")
+                .help("TOML file with an [output] section supplying file/target-size; values given on the\
+                    command line take precedence over the file")
+                .takes_value(true))
+            .args(&io_tuning_args()))
+        .subcommand(SubCommand::with_name("mqtt")
+            .about("bridge the filter's peak frames to an MQTT broker for live lab dashboards, analogous to a\
+                Modbus-to-MQTT bridge")
+            .arg(Arg::with_name("broker")
+                .short("b")
+                .long("mqtt-broker")
+                .value_name("url")
+                .help("URL of the MQTT broker to publish to, e.g. mqtt://localhost:1883")
                 .takes_value(true)
                 .required(true)
-                .index(2)))
+                .index(1))
+            .arg(Arg::with_name("device id")
+                .short("i")
+                .long("device-id")
+                .value_name("device id")
+                .help("identifier used in the published topics, mbfilter/<id>/peaks and mbfilter/<id>/status")
+                .takes_value(true)
+                .default_value("mbfilter0"))
+            .arg(Arg::with_name("binary")
+                .long("binary")
+                .help("publish batched 12-byte binary frames instead of per-peak JSON")
+                .takes_value(false)))
         .subcommand(SubCommand::with_name("status")
-            .about("command that returns the current state of the hardware filter with the currently loaded configuration"))
+            .about("command that returns the current state of the hardware filter with the currently loaded configuration")
+            .arg(control_arg()))
         .subcommand(SubCommand::with_name("stop")
-            .about("stops the filter if it is running"))
+            .about("stops the filter if it is running")
+            .arg(control_arg()))
         .get_matches();
 
     // configure subcommand
     if let Some(matches) = matches.subcommand_matches("configure") {
+        // parse and validate the config before touching the hardware at all, so a bad TOML
+        // file or bad CLI arguments are rejected without ever opening the filter device
+        let filter_settings = matches.value_of("config")
+            .map(|p| MBSettings::from_file(Path::new(p)))
+            .transpose()?
+            .and_then(|settings| settings.filter)
+            .unwrap_or_default();
+        let k = matches.value_of("k").map(String::from).or(filter_settings.k).ok_or(MBError::InvalidInput)?;
+        let l = matches.value_of("l").map(String::from).or(filter_settings.l).ok_or(MBError::InvalidInput)?;
+        let m = matches.value_of("m").map(String::from).or(filter_settings.m).ok_or(MBError::InvalidInput)?;
+        let pthresh = matches.value_of("pthresh").map(String::from).or(filter_settings.pthresh).ok_or(MBError::InvalidInput)?;
+        let dead_time = matches.value_of("dead-time").map(String::from).or(filter_settings.dead_time).ok_or(MBError::InvalidInput)?;
+        let config = MBConfig::new_from_str(&k, &l, &m, &pthresh, &dead_time)?;
+        config.validate().map_err(|_| MBError::InvalidInput)?;
         let filter = MBFilter::new()?;
-        let config = MBConfig::new_from_str(
-                    matches.value_of("k").unwrap(),
-                    matches.value_of("l").unwrap(),
-                    matches.value_of("m").unwrap(),
-                    matches.value_of("pthresh").unwrap(),
-                    matches.value_of("dead-time").unwrap())?;
         filter.configure(config);
         ()
     }
 
     // start subcommand
     if let Some(matches) = matches.subcommand_matches("start") {
+        // parse and validate the config before touching the hardware at all, so a bad TOML
+        // file or bad CLI arguments are rejected without ever opening the filter device
+        let settings = matches.value_of("config")
+            .map(|p| MBSettings::from_file(Path::new(p)))
+            .transpose()?
+            .unwrap_or_default();
+        let output_settings = settings.output.unwrap_or_default();
+        let io_settings = settings.io.unwrap_or_default();
+        let io_tuning = IoTuning::from_matches(matches, &io_settings)?;
+        let requested_pc = match matches.value_of("target file size") {
+            Some(v) => u64::from_str_radix(v, 10)?,
+            None => output_settings.target_size.ok_or(MBError::InvalidInput)?,
+        };
+        let filepath = matches.value_of("output file").map(String::from)
+            .or(output_settings.file)
+            .ok_or(MBError::InvalidInput)?;
         let mut filter = MBFilter::new()?;
-        let requested_pc = u64::from_str_radix(matches.value_of("target file size").unwrap(), 10)?;
-        let filepath = matches.value_of("output file").unwrap();
-        let path = Path::new(filepath);
-        let ofile = File::create(&path)?;
-        let mut ofile = BufWriter::new(ofile);
-        let mut fc: u64 = 0;
+        let ofile = tokio::fs::File::create(&filepath).await?;
+        let mut ofile = tokio::io::BufWriter::new(ofile);
+
         match filter.state() {
             MBFState::Ready => {
+                // producer/consumer split: the reader below drains the hardware FIFO as fast
+                // as possible and hands buffers to this task over a bounded queue, so a slow
+                // disk can no longer stall the read side and risk FIFOFull
+                let queue = Arc::new(FrameQueue::new(io_tuning.backlog));
+                let reader_queue = queue.clone();
+
                 filter.start();
-                let mut buffer: [u8; 12*2048] = [0; 12*2048];
-                while fc < requested_pc {
-                    let bytes_read = filter.read(&mut buffer)?;
-                    debug!("{} bytes read", bytes_read);
-                    let mut pos = 0;
-                    while pos < (&buffer[..bytes_read]).len() {
-                        let bytes_written = ofile.write(&buffer[pos..bytes_read])?;
-                        pos += bytes_written;
+                let reader = tokio::spawn(async move {
+                    let mut buffer: [u8; 12*2048] = [0; 12*2048];
+                    let mut fc: u64 = 0;
+                    let mut dropped: u64 = 0;
+                    while fc < requested_pc {
+                        if io_tuning.throttle_ms > 0 {
+                            tokio::time::sleep(Duration::from_millis(io_tuning.throttle_ms)).await;
+                        }
+                        let bytes_read = filter.read(&mut buffer)?;
+                        debug!("{} bytes read", bytes_read);
+                        fc += bytes_read as u64;
+                        let chunk = buffer[..bytes_read].to_vec();
+                        match reader_queue.push(chunk, io_tuning.drop_oldest).await {
+                            PushOutcome::Pushed => {},
+                            PushOutcome::Dropped => {
+                                dropped += 1;
+                                warn!("output queue full, dropped oldest buffer ({} dropped so far)", dropped);
+                            },
+                            PushOutcome::Closed(_) => break,
+                        }
+                    }
+                    filter.stop();
+                    reader_queue.close().await;
+                    Ok::<(), MBError>(())
+                });
+
+                loop {
+                    let chunk = match queue.pop().await {
+                        Some(chunk) => chunk,
+                        None => break,
                     };
-                    fc += bytes_read as u64;
+                    match tokio::time::timeout(Duration::from_millis(io_tuning.timeout_ms), ofile.write_all(&chunk)).await {
+                        Ok(write_result) => write_result?,
+                        Err(_) => {
+                            debug!("write to output file timed out after {} ms", io_tuning.timeout_ms);
+                            queue.close().await;
+                            break;
+                        },
+                    }
                 }
-                filter.stop();
+                ofile.flush().await?;
+                reader.await??;
             },
             _ => Err(MBError::WrongState)?,
         }
     }
 
 
+    // mqtt subcommand
+    if let Some(matches) = matches.subcommand_matches("mqtt") {
+        let mut filter = MBFilter::new()?;
+        let broker_url = matches.value_of("broker").unwrap();
+        let device_id = matches.value_of("device id").unwrap();
+        let binary_mode = matches.is_present("binary");
+        let peaks_topic = format!("mbfilter/{}/peaks", device_id);
+        let status_topic = format!("mbfilter/{}/status", device_id);
+
+        let mut mqttoptions = MqttOptions::parse_url(broker_url)?;
+        mqttoptions.set_last_will(LastWill::new(status_topic.clone(), "offline", QoS::AtLeastOnce, true));
+        let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+
+        // drives the keep-alive/ack handling for `client`; publishes on `client` only queue the
+        // packet, this loop is what actually flushes it to the broker
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = eventloop.poll().await {
+                    debug!("mqtt eventloop error: {:?}", e);
+                    break;
+                }
+            }
+        });
+
+        match filter.state() {
+            MBFState::Ready => {
+                filter.start();
+                // only publish when the state actually transitions -- publishing on every read
+                // iteration floods the broker with retained QoS::AtLeastOnce messages for a
+                // value that almost never changes between reads. `mem::discriminant` compares
+                // just the variant (Ready/Running/...), not fields like frame_count, which is
+                // exactly what "changed state" should mean here.
+                let mut last_published = std::mem::discriminant(&filter.state());
+                publish_status(&client, &status_topic, &filter.state()).await?;
+                let mut buffer: [u8; 12*2048] = [0; 12*2048];
+                let read_result: Result<(), MBError> = loop {
+                    let bytes_read = match filter.read(&mut buffer) {
+                        Ok(bytes_read) => bytes_read,
+                        Err(e) => break Err(e),
+                    };
+                    debug!("{} bytes read", bytes_read);
+                    if binary_mode {
+                        client.publish(peaks_topic.clone(), QoS::AtMostOnce, false, buffer[..bytes_read].to_vec()).await?;
+                    } else {
+                        for frame in buffer[..bytes_read].chunks_exact(12) {
+                            // `from_bytes` is decoding raw hardware bytes, so -- like every other
+                            // decode path in this file (`MBConfig::new_from_str`, `filter.read`)
+                            // -- treat it as fallible rather than assuming it can't fail; a
+                            // malformed peak is logged and skipped instead of taking the whole
+                            // bridge down. `moessbauer_data` isn't vendored in this tree, so this
+                            // signature (and the `Serialize` impl on `MeasuredPeak`) can't be
+                            // checked here -- confirm both at build time.
+                            let peak = match MeasuredPeak::from_bytes(frame) {
+                                Ok(peak) => peak,
+                                Err(e) => {
+                                    warn!("failed to decode a peak frame, skipping: {:?}", e);
+                                    continue;
+                                },
+                            };
+                            let payload = serde_json::to_vec(&peak)?;
+                            client.publish(peaks_topic.clone(), QoS::AtMostOnce, false, payload).await?;
+                        }
+                    }
+                    let state = filter.state();
+                    let discriminant = std::mem::discriminant(&state);
+                    if discriminant != last_published {
+                        last_published = discriminant;
+                        publish_status(&client, &status_topic, &state).await?;
+                    }
+                };
+                filter.stop();
+                publish_status(&client, &status_topic, &filter.state()).await?;
+                read_result?;
+            },
+            _ => Err(MBError::WrongState)?,
+        }
+    }
+
     // stop subcommand
-    if let Some(_) = matches.subcommand_matches("stop") {
-        unimplemented!("stop subcommand")
+    if let Some(matches) = matches.subcommand_matches("stop") {
+        let control_addr = matches.value_of("control").unwrap();
+        let response = query_control(control_addr, "stop").await?;
+        if let Some(error) = response.get("error").and_then(|v| v.as_str()) {
+            println!("server reported an error: {}", error);
+        }
     }
 
     // status subcommand
-    if let Some(_) = matches.subcommand_matches("status") {
-        if let Ok(filter) = MBFilter::new() {
-            let config = filter.configuration();
-            let state = filter.state();
-            println!("{}\nCurrent filter State:\n{}", config, state);
+    if let Some(matches) = matches.subcommand_matches("status") {
+        let control_addr = matches.value_of("control").unwrap();
+        let response = query_control(control_addr, "status").await?;
+        match response.get("error").and_then(|v| v.as_str()) {
+            Some(error) => println!("server reported an error: {}", error),
+            None => {
+                let config = response.get("config").and_then(|v| v.as_str()).unwrap_or("");
+                let state = response.get("state").and_then(|v| v.as_str()).unwrap_or("");
+                println!("{}\nCurrent filter State:\n{}", config, state);
+            },
         }
     }
 
     // server subcommand
     if let Some(matches) = matches.subcommand_matches("server") {
+        // parse and validate the config before touching the hardware at all, so a bad TOML
+        // file or bad CLI arguments are rejected without ever opening the filter device
+        let settings = matches.value_of("config")
+            .map(|p| MBSettings::from_file(Path::new(p)))
+            .transpose()?
+            .unwrap_or_default();
+        let server_settings = settings.server.unwrap_or_default();
+        let io_settings = settings.io.unwrap_or_default();
+        let acl_settings = settings.acl.unwrap_or_default();
+        let io_tuning = IoTuning::from_matches(matches, &io_settings)?;
+        let (allow, deny) = resolve_acl(matches, &acl_settings)?;
+        let allow = Arc::new(allow);
+        let deny = Arc::new(deny);
+        // a transport chosen on the command line replaces the file's listen/unix choice as a
+        // whole, so `--listen` overrides a `unix` entry in the file and vice versa
+        let (listen, unix) = if matches.is_present("listen") || matches.is_present("unix") {
+            (matches.value_of("listen").map(String::from), matches.value_of("unix").map(String::from))
+        } else {
+            (server_settings.listen, server_settings.unix)
+        };
+        if listen.is_some() == unix.is_some() {
+            Err(MBError::InvalidInput)?
+        }
+        let max_connections: usize = match matches.value_of("max-connections") {
+            Some(v) => v.parse().map_err(|_| MBError::InvalidInput)?,
+            None => server_settings.max_connections.unwrap_or(1),
+        };
         let filter = Arc::new(Mutex::new(MBFilter::new()?));
         let state_check_filter_copy = filter.clone();
-        let socket_address: std::net::SocketAddr = matches.value_of("listen").unwrap().parse()?;
+        let active_connections = Arc::new(AtomicUsize::new(0));
+        let control_addr: std::net::SocketAddr = matches.value_of("control").unwrap().parse()?;
+        let control_listener = TcpListener::bind(control_addr).await.map_err(MBError::BindError)?;
+        let control_filter = filter.clone();
+        tokio::spawn(async move {
+            run_control_socket(control_listener, control_filter).await;
+        });
+        // the HTTP routes below are a convenience mirror of the TCP control socket for clients
+        // that would rather speak plain HTTP than the line-delimited JSON protocol; they go
+        // through the same ACL as the websocket route so they can't be used to bypass it
+        let http_allow = allow.clone();
+        let http_deny = deny.clone();
+        let status_filter = filter.clone();
+        let status_route = warp::path("status")
+            .and(warp::get())
+            .and(client_acl(http_allow, http_deny))
+            .and_then(move || {
+                let filter = status_filter.clone();
+                async move {
+                    let locked_filter = filter.lock().await;
+                    Ok::<_, warp::reject::Rejection>(warp::reply::json(&json!({
+                        "config": locked_filter.configuration().to_string(),
+                        "state": locked_filter.state().to_string(),
+                    })))
+                }
+            });
+        let http_allow = allow.clone();
+        let http_deny = deny.clone();
+        let stop_filter = filter.clone();
+        let stop_route = warp::path("stop")
+            .and(warp::post())
+            .and(client_acl(http_allow, http_deny))
+            .and_then(move || {
+                let filter = stop_filter.clone();
+                async move {
+                    let mut locked_filter = filter.lock().await;
+                    teardown_filter(&mut locked_filter);
+                    Ok::<_, warp::reject::Rejection>(warp::reply::json(&json!({ "ok": true })))
+                }
+            });
         let route = warp::path("websocket")
+            .and(client_acl(allow, deny))
+            .and(admission_control(active_connections, max_connections))
             .and(warp::query::query())
-            .and_then(validate_config)
-            .and_then(move |config| check_and_configure_filter(config, state_check_filter_copy.clone()))
+            .and_then(move |slot, config| validate_config(slot, config))
+            .and_then(move |slot, config| check_and_configure_filter(slot, config, state_check_filter_copy.clone()))
             .and(warp::ws())
-            .map(move |config, ws| {
-                ws_handler(filter.clone(), config, ws)
-            });
-        warp::serve(route)
-            .run(socket_address)
-            .await;
+            .map(move |slot, config, ws| {
+                ws_handler(filter.clone(), slot, io_tuning, config, ws)
+            })
+            .or(status_route)
+            .or(stop_route)
+            .recover(handle_rejection);
+        if let Some(unix_path) = unix {
+            let incoming = bind_unix_socket(&unix_path)?;
+            warp::serve(route)
+                .run_incoming(incoming)
+                .await;
+        } else {
+            let socket_address: std::net::SocketAddr = listen.unwrap().parse()?;
+            warp::serve(route)
+                .run(socket_address)
+                .await;
+        }
     }
     Ok(())
 }
 
-async fn validate_config(config: MBConfig) -> Result<MBConfig, warp::reject::Rejection> {
+// binds a Unix domain socket for the server to listen on, removing a stale socket file left
+// behind by a previous unclean shutdown and restricting access to owner and group
+fn bind_unix_socket(path: &str) -> Result<UnixListenerStream, MBError> {
+    let socket_path = Path::new(path);
+    if socket_path.exists() {
+        std::fs::remove_file(socket_path).map_err(MBError::BindError)?;
+    }
+    let listener = UnixListener::bind(socket_path).map_err(MBError::BindError)?;
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o660))
+        .map_err(MBError::BindError)?;
+    Ok(UnixListenerStream::new(listener))
+}
+
+// a small line-delimited JSON control socket, separate from the websocket server, that lets the
+// `stop`/`status` subcommands talk to a running `server` process instead of opening the
+// hardware themselves (which fails with MBError::WrongState while the server already owns it).
+// One connection is handled at a time per accepted socket; accept errors are logged and do not
+// bring the control plane down
+async fn run_control_socket(listener: TcpListener, filter: Arc<Mutex<MBFilter>>) {
+    loop {
+        let (socket, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("control socket failed to accept a connection: {}", e);
+                continue;
+            },
+        };
+        let filter = filter.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_control_connection(socket, filter).await {
+                warn!("control socket connection ended with an error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_control_connection(socket: TcpStream, filter: Arc<Mutex<MBFilter>>) -> Result<(), MBError> {
+    let (read_half, mut write_half) = socket.into_split();
+    let mut reader = BufReader::new(read_half);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let request: serde_json::Value = serde_json::from_str(line.trim()).map_err(|_| MBError::InvalidInput)?;
+    let response = match request.get("cmd").and_then(|v| v.as_str()) {
+        Some("status") => {
+            let locked_filter = filter.lock().await;
+            json!({
+                "config": locked_filter.configuration().to_string(),
+                "state": locked_filter.state().to_string(),
+            })
+        },
+        Some("stop") => {
+            let mut locked_filter = filter.lock().await;
+            teardown_filter(&mut locked_filter);
+            json!({ "ok": true })
+        },
+        _ => json!({ "error": "unknown command" }),
+    };
+    write_half.write_all(response.to_string().as_bytes()).await?;
+    write_half.write_all(b"\n").await?;
+    Ok(())
+}
+
+// connects to a running server's control socket, issues a single command and returns its
+// parsed JSON response; used by the `stop` and `status` subcommands
+async fn query_control(addr: &str, cmd: &str) -> Result<serde_json::Value, MBError> {
+    let socket_addr: std::net::SocketAddr = addr.parse().map_err(|_| MBError::InvalidInput)?;
+    let stream = TcpStream::connect(socket_addr).await?;
+    let (read_half, mut write_half) = stream.into_split();
+    write_half.write_all(json!({ "cmd": cmd }).to_string().as_bytes()).await?;
+    write_half.write_all(b"\n").await?;
+    let mut reader = BufReader::new(read_half);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    serde_json::from_str(line.trim()).map_err(|_| MBError::InvalidInput)
+}
+
+// admission-control layer run ahead of validate_config: atomically reserves one of
+// `max_connections` slots so a saturated server answers with 503 before any configuration
+// work begins, rather than letting a second client reach check_and_configure_filter only to
+// be rejected after the HTTP/websocket upgrade has already happened. The returned
+// `ConnectionSlot` is threaded through the rest of the filter chain and releases its
+// reservation the moment it's dropped, so a rejection anywhere downstream -- ours or one of
+// warp's own (malformed query string, a GET with no Upgrade header, ...) -- frees the slot
+// without every rejection branch needing to remember to do it.
+fn admission_control(
+    active_connections: Arc<AtomicUsize>,
+    max_connections: usize,
+) -> impl Filter<Extract = (ConnectionSlot,), Error = warp::reject::Rejection> + Clone {
+    warp::any().and_then(move || {
+        let active_connections = active_connections.clone();
+        async move {
+            loop {
+                let current = active_connections.load(Ordering::SeqCst);
+                if current >= max_connections {
+                    return Err(warp::reject::custom(ServerSaturated));
+                }
+                if active_connections
+                    .compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    // low watermark: once we are down to the last free slot, let operators know
+                    // the server is close to rejecting connections. Meaningless noise when
+                    // there's only ever been one slot to begin with, so only warn once there's
+                    // more than one and it's actually running low
+                    if max_connections > 1 && max_connections.saturating_sub(current + 1) <= 1 {
+                        warn!("connection slots nearly exhausted: {}/{} in use", current + 1, max_connections);
+                    }
+                    return Ok(ConnectionSlot {
+                        inner: Arc::new(ConnectionSlotInner {
+                            active_connections: active_connections.clone(),
+                        }),
+                    });
+                }
+            }
+        }
+    })
+}
+
+async fn handle_rejection(err: warp::reject::Rejection) -> Result<impl warp::Reply, warp::reject::Rejection> {
+    if err.find::<ServerSaturated>().is_some() {
+        let mut response = warp::reply::with_status(
+            "server saturated, try again later",
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        ).into_response();
+        response.headers_mut().insert(
+            warp::http::header::RETRY_AFTER,
+            warp::http::HeaderValue::from_static("1"),
+        );
+        Ok(response)
+    } else if err.find::<ClientNotAllowed>().is_some() {
+        Ok(warp::reply::with_status(
+            "client address is not permitted to connect to this server",
+            warp::http::StatusCode::FORBIDDEN,
+        ).into_response())
+    } else {
+        Err(err)
+    }
+}
+
+// publishes the current filter state to the retained status topic so subscribers always see
+// the latest Ready/Running/FIFOFull/Halted transition, even ones that connect after it happened
+async fn publish_status(client: &AsyncClient, status_topic: &str, state: &MBFState) -> Result<(), rumqttc::ClientError> {
+    let status = match state {
+        MBFState::Ready => "ready",
+        MBFState::Running{..} => "running",
+        MBFState::FIFOFull{..} => "fifo_full",
+        MBFState::Halted => "halted",
+        MBFState::InvalidParameters => "invalid_parameters",
+    };
+    client.publish(status_topic, QoS::AtLeastOnce, true, status).await
+}
+
+// on an `Err` return here the caller's `slot` simply goes out of scope, releasing the
+// admission-control reservation -- no explicit decrement needed, and no risk of forgetting one
+// of the rejection branches below
+async fn validate_config(slot: ConnectionSlot, config: MBConfig) -> Result<(ConnectionSlot, MBConfig), warp::reject::Rejection> {
     match config.validate() {
-        Ok(config) => Ok(config),
+        Ok(config) => Ok((slot, config)),
         Err(_) => Err(warp::reject::custom(MBHTTPError("Invalid Config"))),
     }
 }
 
-async fn check_and_configure_filter(config: MBConfig, filter: Arc<Mutex<MBFilter>>) -> Result<MBConfig, warp::reject::Rejection> {
+async fn check_and_configure_filter(slot: ConnectionSlot, config: MBConfig, filter: Arc<Mutex<MBFilter>>) -> Result<(ConnectionSlot, MBConfig), warp::reject::Rejection> {
     if let Ok(ref mut unlocked_filter) = filter.try_lock() {
         match unlocked_filter.state() {
             MBFState::Ready | MBFState::InvalidParameters => {
@@ -211,18 +947,18 @@ async fn check_and_configure_filter(config: MBConfig, filter: Arc<Mutex<MBFilter
                 if read_config != config {
                     return Err(warp::reject::custom(MBHTTPError("Filter config load error")));
                 }
-                return Ok(read_config)
+                return Ok((slot, read_config))
             },
             _ => return Err(warp::reject::custom(MBHTTPError("Filter already running"))),
         }
     }
-    return Err(warp::reject::custom(MBHTTPError("Filter already running")));
+    Err(warp::reject::custom(MBHTTPError("Filter already running")))
 }
 
 // the ws.on_upgrade gives us the reply we want but we still need to handle the rejections that
 // can occurr before we reach this function that actually replies with a valid HTTP response
-fn ws_handler(filter: Arc<Mutex<MBFilter>>, _config: MBConfig, ws: warp::ws::Ws) -> impl warp::Reply {
-    ws.on_upgrade(|websocket| {
+fn ws_handler(filter: Arc<Mutex<MBFilter>>, slot: ConnectionSlot, io_tuning: IoTuning, _config: MBConfig, ws: warp::ws::Ws) -> impl warp::Reply {
+    ws.on_upgrade(move |websocket| {
         async move {
             {
                 let mut locked_filter = filter.lock().await;
@@ -230,12 +966,32 @@ fn ws_handler(filter: Arc<Mutex<MBFilter>>, _config: MBConfig, ws: warp::ws::Ws)
             }
             let (mut wstx, mut wsrx) = websocket.split();
             let reader_filter_clone = filter.clone();
+            let writer_filter_clone = filter.clone();
             let control_filter_clone = filter.clone();
-            // the task to read a filter
+            // each task below owns a clone of the same reservation; the slot is only actually
+            // released once all three have finished and dropped their clone -- if one task
+            // exits early the other two are still running the connection, so the slot must
+            // stay reserved until they're done too
+            let reader_slot = slot.clone();
+            let writer_slot = slot.clone();
+            let control_slot = slot;
+
+            // producer/consumer split: the reader below drains the hardware FIFO as fast as
+            // possible and hands buffers to the writer over a bounded queue, so a slow
+            // websocket client can no longer stall the read side and risk FIFOFull
+            let queue = Arc::new(FrameQueue::new(io_tuning.backlog));
+            let reader_queue = queue.clone();
+
+            // the task to read the filter
             tokio::spawn(async move {
+                let _reader_slot = reader_slot;
                 let mut buffer: [u8;2048*12] = [0;2048*12];
                 let mut count;
+                let mut dropped: u64 = 0;
                 loop {
+                    if io_tuning.throttle_ms > 0 {
+                        tokio::time::sleep(Duration::from_millis(io_tuning.throttle_ms)).await;
+                    }
                     {
                         let mut filter = reader_filter_clone.lock().await;
                         debug!("aquired filter lock for reading");
@@ -244,24 +1000,52 @@ fn ws_handler(filter: Arc<Mutex<MBFilter>>, _config: MBConfig, ws: warp::ws::Ws)
                     match count {
                         Ok(count) => {
                             debug!("read {} bytes", count);
-                            if count % 12 == 0 {
-                                match wstx.send(warp::ws::Message::binary(&buffer[..count])).await {
-                                    Ok(_) => {},
-                                    Err(e) => {
-                                        debug!("Error encountered writing to the websocket: {:?}", e);
-                                        clean_up(reader_filter_clone.clone()).await;
-                                        break;
-                                    }
-                                }
-                            } else {
+                            if count % 12 != 0 {
                                 debug!("Did not read a multiple of 12 bytes from filter");
                                 clean_up(reader_filter_clone.clone()).await;
+                                reader_queue.close().await;
                                 break;
                             }
+                            let chunk = buffer[..count].to_vec();
+                            match reader_queue.push(chunk, io_tuning.drop_oldest).await {
+                                PushOutcome::Pushed => {},
+                                PushOutcome::Dropped => {
+                                    dropped += 1;
+                                    warn!("websocket send queue full, dropped oldest buffer ({} dropped so far)", dropped);
+                                },
+                                PushOutcome::Closed(_) => break,
+                            }
                         },
                         Err(e) => {
                             debug!("Error encountered reading filter: {}", e);
                             clean_up(reader_filter_clone.clone()).await;
+                            reader_queue.close().await;
+                            break;
+                        },
+                    }
+                }
+            });
+            // the task that drains the queue to the websocket; a client that can't keep up
+            // within timeout_ms is torn down instead of stalling the hardware FIFO drain
+            tokio::spawn(async move {
+                let _writer_slot = writer_slot;
+                loop {
+                    let chunk = match queue.pop().await {
+                        Some(chunk) => chunk,
+                        None => break,
+                    };
+                    match tokio::time::timeout(Duration::from_millis(io_tuning.timeout_ms), wstx.send(warp::ws::Message::binary(chunk))).await {
+                        Ok(Ok(())) => {},
+                        Ok(Err(e)) => {
+                            debug!("Error encountered writing to the websocket: {:?}", e);
+                            clean_up(writer_filter_clone.clone()).await;
+                            queue.close().await;
+                            break;
+                        },
+                        Err(_) => {
+                            debug!("websocket send timed out after {} ms", io_tuning.timeout_ms);
+                            clean_up(writer_filter_clone.clone()).await;
+                            queue.close().await;
                             break;
                         },
                     }
@@ -269,6 +1053,7 @@ fn ws_handler(filter: Arc<Mutex<MBFilter>>, _config: MBConfig, ws: warp::ws::Ws)
             });
             // task that receives the stop command and stops the filter
             tokio::spawn(async move {
+                let _control_slot = control_slot;
                 while let Some(result) = wsrx.next().await {
                     match result {
                         Ok(msg) => {
@@ -295,9 +1080,11 @@ fn ws_handler(filter: Arc<Mutex<MBFilter>>, _config: MBConfig, ws: warp::ws::Ws)
     })
 }
 
-async fn clean_up(filter: Arc<Mutex<MBFilter>>) {
-    let mut locked_filter = filter.lock().await;
-    debug!("filter lock aquired for cleanup operations");
+// stops the filter if it's running and drains whatever is left in the FIFO, bringing the
+// filter back to the ready state; a filter found with invalid parameters is left as it is.
+// Shared by the
+// websocket disconnect path (clean_up) and the control socket's stop command
+fn teardown_filter(locked_filter: &mut MBFilter) {
     match locked_filter.state() {
         MBFState::InvalidParameters => {},
         MBFState::FIFOFull{frame_count: _} => {
@@ -317,3 +1104,12 @@ async fn clean_up(filter: Arc<Mutex<MBFilter>>) {
         },
     }
 }
+
+// the connection's `ConnectionSlot` is released separately, once the last of the reader/writer/
+// control tasks' clones of it is dropped -- not from here, since this is called from more than
+// one of those tasks
+async fn clean_up(filter: Arc<Mutex<MBFilter>>) {
+    let mut locked_filter = filter.lock().await;
+    debug!("filter lock aquired for cleanup operations");
+    teardown_filter(&mut locked_filter);
+}